@@ -3,23 +3,28 @@
 //! This provides one component, KVStoreComponent, which provides
 //! a system call inteface to kv storage.
 //!
+//! The KV region's flash placement (which instance, and the base address
+//! and block size within it) is named by a `FlashProvider`, so a board can
+//! put the region on a flash instance distinct from the one it boots from.
+//!
 //! Usage
 //! -----
 //! ```rust
 //! let nonvolatile_storage = components::kv_store::KVStoreComponent::new(
 //!     board_kernel,
-//!     &sam4l::flashcalw::FLASH_CONTROLLER,
-//!     0x60000,
+//!     &flash_provider,
 //!     0x20000,
-//!     &_sstorage as *const u8 as usize,
-//!     &_estorage as *const u8 as usize,
+//!     &mut KV_READ_BUF,
+//!     &mut KV_PAGE_BUF,
+//!     capsules::kv_store::NoCacheImpl,
 //! )
-//! .finalize(components::nv_storage_component_helper!(
-//!     sam4l::flashcalw::FLASHCALW
+//! .finalize(components::kv_store_component_helper!(
+//!     sam4l::flashcalw::FLASHCALW,
+//!     0x200
 //! ));
 //! ```
 
-use capsules::kv_store::KVStoreDriver;
+use capsules::kv_store::{FlashConfig, KVStoreCache, KVStoreDriver};
 use capsules::virtual_flash::FlashUser;
 use capsules::virtual_flash::MuxFlash;
 use core::mem::MaybeUninit;
@@ -62,76 +67,152 @@ impl<F: 'static + hil::flash::Flash> Component for FlashMuxComponent<F> {
     }
 }
 
+/// Names the flash instance and partition a `KVStoreComponent` should bind
+/// its KV region to. Implement this once per board/partition to place the
+/// KV store on whichever `Flash` instance is appropriate -- including one
+/// separate from the instance the board boots from, e.g. an external QSPI
+/// chip -- without the component needing to know about it.
+pub trait FlashProvider {
+    type F: 'static + hil::flash::Flash;
+
+    /// The `MuxFlash` virtualizing the `Flash` instance this partition
+    /// lives on.
+    fn mux_flash(&'static self) -> &'static MuxFlash<'static, Self::F>;
+    /// Byte address, within that instance's address space, where this
+    /// partition starts.
+    fn base_address(&self) -> usize;
+    /// Size in bytes of one flash block/page on that instance.
+    fn block_size(&self) -> usize;
+}
+
+/// Binds a `FlashProvider`'s partition to the `FlashUser` the component
+/// virtualizes it through, so it can be handed to `TicKVFlashCtrl` as a
+/// `capsules::kv_store::FlashConfig`.
+pub struct ComponentFlashConfig<F: 'static + hil::flash::Flash> {
+    flash: &'static FlashUser<'static, F>,
+    base_address: usize,
+    block_size: usize,
+}
+
+impl<F: 'static + hil::flash::Flash> ComponentFlashConfig<F> {
+    fn new(flash: &'static FlashUser<'static, F>, base_address: usize, block_size: usize) -> Self {
+        Self {
+            flash,
+            base_address,
+            block_size,
+        }
+    }
+}
+
+impl<F: 'static + hil::flash::Flash> FlashConfig for ComponentFlashConfig<F> {
+    type F = FlashUser<'static, F>;
+
+    fn flash(&self) -> &'static Self::F {
+        self.flash
+    }
+
+    fn base_address(&self) -> usize {
+        self.base_address
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
+
 // Setup static space for the objects.
+//
+// The 2-argument form is the common case and opts out of the key/page
+// cache (zero RAM cost); pass an explicit cache type as the middle
+// argument to opt in.
 #[macro_export]
 macro_rules! kv_store_component_helper {
-    ($F:ty, $S:ty) => {{
+    ($F:ty, $S:expr) => {
+        $crate::kv_store_component_helper!($F, capsules::kv_store::NoCacheImpl, $S)
+    };
+    ($F:ty, $C:ty, $S:expr) => {{
         use capsules::kv_store::KVStoreDriver;
         use capsules::virtual_flash::FlashUser;
+        use components::kv_store::ComponentFlashConfig;
         use core::mem::MaybeUninit;
-        use kernel::hil;
         static mut BUF1: MaybeUninit<FlashUser<'static, $F>> = MaybeUninit::uninit();
-        static mut BUF2: MaybeUninit<KVStoreDriver<'static, FlashUser<'static, $F>, $S>> =
+        static mut BUF2: MaybeUninit<ComponentFlashConfig<$F>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<KVStoreDriver<'static, ComponentFlashConfig<$F>, $C, { $S }>> =
             MaybeUninit::uninit();
-        (&mut BUF1, &mut BUF2)
+        (&mut BUF1, &mut BUF2, &mut BUF3)
     };};
 }
 
-pub struct KVStoreComponent<F: 'static + hil::flash::Flash, const S: usize> {
+pub struct KVStoreComponent<P: 'static + FlashProvider, C: 'static + KVStoreCache, const S: usize> {
     board_kernel: &'static kernel::Kernel,
-    mux_flash: &'static MuxFlash<'static, F>,
-    region_offset: usize,
+    provider: &'static P,
     length: usize,
     read_buf: &'static mut [u8; S],
-    page_buffer: &'static mut F::Page,
+    page_buffer: &'static mut <P::F as hil::flash::Flash>::Page,
+    cache: C,
 }
 
-impl<F: 'static + hil::flash::Flash, const S: usize> KVStoreComponent<F, { S }> {
+impl<P: 'static + FlashProvider, C: 'static + KVStoreCache, const S: usize>
+    KVStoreComponent<P, C, { S }>
+{
     pub fn new(
         board_kernel: &'static kernel::Kernel,
-        mux_flash: &'static MuxFlash<'static, F>,
-        region_offset: usize,
+        provider: &'static P,
         length: usize,
         read_buf: &'static mut [u8; S],
-        page_buffer: &'static mut F::Page,
+        page_buffer: &'static mut <P::F as hil::flash::Flash>::Page,
+        cache: C,
     ) -> Self {
         Self {
             board_kernel,
-            mux_flash,
-            region_offset,
+            provider,
             length,
             read_buf,
             page_buffer,
+            cache,
         }
     }
 }
 
-impl<F: 'static + hil::flash::Flash, const S: usize> Component for KVStoreComponent<F, { S }> {
+impl<P: 'static + FlashProvider, C: 'static + KVStoreCache, const S: usize> Component
+    for KVStoreComponent<P, C, { S }>
+{
     type StaticInput = (
-        &'static mut MaybeUninit<FlashUser<'static, F>>,
-        &'static mut MaybeUninit<KVStoreDriver<'static, FlashUser<'static, F>, S>>,
+        &'static mut MaybeUninit<FlashUser<'static, P::F>>,
+        &'static mut MaybeUninit<ComponentFlashConfig<P::F>>,
+        &'static mut MaybeUninit<KVStoreDriver<'static, ComponentFlashConfig<P::F>, C, S>>,
     );
-    type Output = &'static KVStoreDriver<'static, FlashUser<'static, F>, S>;
+    type Output = &'static KVStoreDriver<'static, ComponentFlashConfig<P::F>, C, S>;
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
 
         let virtual_flash = static_init_half!(
             static_buffer.0,
-            FlashUser<'static, F>,
-            FlashUser::new(self.mux_flash)
+            FlashUser<'static, P::F>,
+            FlashUser::new(self.provider.mux_flash())
         );
 
-        let driver = static_init_half!(
+        let config = static_init_half!(
             static_buffer.1,
-            KVStoreDriver<'static, FlashUser<'static, F>, S>,
-            KVStoreDriver::new(
+            ComponentFlashConfig<P::F>,
+            ComponentFlashConfig::new(
                 virtual_flash,
+                self.provider.base_address(),
+                self.provider.block_size(),
+            )
+        );
+
+        let driver = static_init_half!(
+            static_buffer.2,
+            KVStoreDriver<'static, ComponentFlashConfig<P::F>, C, S>,
+            KVStoreDriver::new(
+                config,
                 self.board_kernel.create_grant(&grant_cap),
                 self.read_buf,
                 self.length,
                 self.page_buffer,
-                self.region_offset,
+                self.cache,
             )
         );
         virtual_flash.set_client(driver);
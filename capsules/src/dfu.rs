@@ -0,0 +1,625 @@
+//! A power-failure-safe firmware-update (DFU) capsule.
+//!
+//! This capsule manages two equal-size flash partitions, ACTIVE and DFU,
+//! plus a small STATE partition and a single-page SCRATCH partition, and
+//! performs a resumable swap between ACTIVE and DFU driven entirely by
+//! what is recorded in STATE.
+//!
+//! An application stages a new image by writing it into the DFU partition
+//! a page at a time (`write_firmware`), then requests the update with
+//! `mark_updated`, which sets STATE's magic to `SWAP_MAGIC` and starts the
+//! swap. The swap proceeds page-by-page, and each page's copy is itself
+//! three sub-steps, tracked in STATE alongside the page progress index so
+//! a reset mid-page resumes correctly rather than re-deriving a value
+//! from a side that's already been overwritten:
+//!
+//! 1. ACTIVE's old content is staged into SCRATCH (sub-step
+//!    `SCRATCH_DONE`) before anything else is touched.
+//! 2. DFU's old content is written into ACTIVE (sub-step `ACTIVE_DONE`).
+//! 3. SCRATCH's staged content is written into DFU, and the page
+//!    progress index advances (sub-step back to `NONE`).
+//!
+//! Resuming re-derives whatever a fresh attempt would have needed from
+//! whichever side is still intact for the recorded sub-step: at
+//! `SCRATCH_DONE`, DFU hasn't been touched yet, so it's simply re-read
+//! before writing ACTIVE, and ACTIVE's original content is read back from
+//! SCRATCH; at `ACTIVE_DONE`, DFU's old content is gone
+//! (ACTIVE now holds it) but SCRATCH still has ACTIVE's, so that's read
+//! back for the final write into DFU. This is the same
+//! `read_complete`/`write_complete`/`erase_complete`-driven continuation
+//! style `KVStoreDriver` uses to cooperate with the single-threaded
+//! kernel.
+//!
+//! Once booted into the swapped image, the application must call
+//! `mark_booted`, which rewrites STATE's magic to `BOOT_MAGIC`. If it
+//! crashes before doing so, the next boot finds STATE still holding
+//! `SWAP_MAGIC` with progress at its maximum (the swap ran to completion
+//! but was never confirmed) and swaps again -- this time under
+//! `REVERT_MAGIC` -- which reverts ACTIVE and DFU back to what they held
+//! before the update. A completed revert is self-confirming (there is
+//! nothing new to boot-test), so it does not re-trigger itself.
+
+use crate::driver;
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Dfu as usize;
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::flash::{self, Client, Flash};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Byte written to STATE's magic field to request a swap. The magic field
+/// is the single byte at offset 0 of STATE; it is not repeated, so the
+/// magic alone does not reveal a STATE write torn by a power loss.
+const SWAP_MAGIC: u8 = 0xF0;
+/// Byte written to STATE's magic field by `mark_booted` once the
+/// swapped image has been confirmed to boot.
+const BOOT_MAGIC: u8 = 0xD0;
+/// Byte written to STATE's magic field while a revert swap is
+/// in progress, i.e. one auto-triggered at boot because the previous
+/// forward swap ran to completion without ever being confirmed via
+/// `mark_booted`. Distinct from `SWAP_MAGIC` so that a completed revert
+/// (`magic == REVERT_MAGIC && progress == num_pages`) is not mistaken for
+/// a completed, still-unconfirmed forward swap and re-reverted forever.
+const REVERT_MAGIC: u8 = 0x0F;
+
+/// Byte offset within the STATE page of the little-endian `u32` progress
+/// index: how many pages of the swap have been committed so far.
+const PROGRESS_OFFSET: usize = 1;
+
+/// Byte offset within the STATE page of the current page's sub-step (one
+/// of the `SUBSTEP_*` constants), so a reset mid-page resumes from
+/// exactly where it left off instead of re-deriving a value from a side
+/// that's already been overwritten.
+const SUBSTEP_OFFSET: usize = 5;
+
+/// Nothing durably changed yet for the page at `progress`; start it from
+/// the top.
+const SUBSTEP_NONE: u8 = 0;
+/// ACTIVE's old content for the page at `progress` has been staged into
+/// SCRATCH; DFU is still untouched, while ACTIVE may already be partly or
+/// fully overwritten, since `SUBSTEP_ACTIVE_DONE` is only persisted once
+/// ACTIVE's write has completed.
+const SUBSTEP_SCRATCH_DONE: u8 = 1;
+/// ACTIVE has been overwritten with DFU's old content; only DFU, from
+/// SCRATCH, remains.
+const SUBSTEP_ACTIVE_DONE: u8 = 2;
+
+fn read_progress(buf: &[u8]) -> usize {
+    u32::from_le_bytes([
+        buf[PROGRESS_OFFSET],
+        buf[PROGRESS_OFFSET + 1],
+        buf[PROGRESS_OFFSET + 2],
+        buf[PROGRESS_OFFSET + 3],
+    ]) as usize
+}
+
+fn write_progress(buf: &mut [u8], progress: usize) {
+    buf[PROGRESS_OFFSET..PROGRESS_OFFSET + 4].copy_from_slice(&(progress as u32).to_le_bytes());
+}
+
+/// Status codes passed back to userspace in the completion callback's
+/// first argument.
+const STATUS_OK: usize = 0;
+const STATUS_FAILED: usize = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operation {
+    None,
+    WriteFirmware,
+    Swap,
+    GetState,
+    MarkBooted,
+}
+
+/// Where a page-copy step of an in-progress swap currently is.
+#[derive(Clone, Copy, PartialEq)]
+enum SwapStep {
+    ReadState,
+    ReadActive,
+    ReadDfu,
+    WriteScratch,
+    CommitScratchDone,
+    WriteActive,
+    CommitActiveDone,
+    /// Resume-only: re-read SCRATCH to recover the content `WriteDfu`
+    /// needs, because `ACTIVE_DONE` means ACTIVE no longer holds it and
+    /// the reset cleared whatever was in RAM.
+    ReadScratch,
+    WriteDfu,
+    WriteState,
+}
+
+pub struct Dfu<'a, F: Flash + 'static> {
+    flash: &'a F,
+    active_buffer: TakeCell<'static, F::Page>,
+    dfu_buffer: TakeCell<'static, F::Page>,
+    state_buffer: TakeCell<'static, F::Page>,
+    /// Page number of the first page of the ACTIVE partition.
+    active_start: usize,
+    /// Page number of the first page of the DFU partition.
+    dfu_start: usize,
+    /// Page number of the (single-page) STATE partition.
+    state_start: usize,
+    /// Page number of the (single-page) SCRATCH partition, used to stage
+    /// ACTIVE's old content durably before either side of the current
+    /// page is overwritten.
+    scratch_start: usize,
+    /// Number of pages in each of ACTIVE and DFU.
+    num_pages: usize,
+    operation: Cell<Operation>,
+    swap_step: Cell<SwapStep>,
+    progress: Cell<usize>,
+    /// Which magic (`SWAP_MAGIC` for a forward swap, `REVERT_MAGIC` for a
+    /// revert) the swap currently in progress persists to STATE as it
+    /// commits each page, so a reset mid-swap resumes under the same
+    /// magic it started with.
+    swap_magic: Cell<u8>,
+    /// The current page's sub-step, mirroring what's persisted in STATE
+    /// at `SUBSTEP_OFFSET`.
+    page_substep: Cell<u8>,
+    apps: Grant<App>,
+    appid: OptionalCell<AppId>,
+}
+
+impl<'a, F: Flash + 'static> Dfu<'a, F> {
+    pub fn new(
+        flash: &'a F,
+        grant: Grant<App>,
+        active_buffer: &'static mut F::Page,
+        dfu_buffer: &'static mut F::Page,
+        state_buffer: &'static mut F::Page,
+        active_start: usize,
+        dfu_start: usize,
+        state_start: usize,
+        scratch_start: usize,
+        num_pages: usize,
+    ) -> Dfu<'a, F> {
+        Dfu {
+            flash,
+            active_buffer: TakeCell::new(active_buffer),
+            dfu_buffer: TakeCell::new(dfu_buffer),
+            state_buffer: TakeCell::new(state_buffer),
+            active_start,
+            dfu_start,
+            state_start,
+            scratch_start,
+            num_pages,
+            operation: Cell::new(Operation::None),
+            swap_step: Cell::new(SwapStep::ReadState),
+            progress: Cell::new(0),
+            swap_magic: Cell::new(SWAP_MAGIC),
+            page_substep: Cell::new(SUBSTEP_NONE),
+            apps: grant,
+            appid: OptionalCell::empty(),
+        }
+    }
+
+    /// Check STATE and resume an in-progress swap if one was requested
+    /// but not completed. Call this once at boot, before any app can
+    /// issue commands.
+    pub fn initalise(&self) {
+        self.operation.set(Operation::Swap);
+        self.swap_step.set(SwapStep::ReadState);
+        self.read_state();
+    }
+
+    fn read_state(&self) {
+        let buf = self.state_buffer.take().unwrap();
+        let _ = self.flash.read_page(self.state_start, buf);
+    }
+
+    fn write_state(&self, magic: u8, progress: usize, substep: u8) {
+        let buf = self.state_buffer.take().unwrap();
+        buf.as_mut()[0] = magic;
+        write_progress(buf.as_mut(), progress);
+        buf.as_mut()[SUBSTEP_OFFSET] = substep;
+        let _ = self.flash.write_page(self.state_start, buf);
+    }
+
+    fn read_active_page(&self) {
+        let buf = self.active_buffer.take().unwrap();
+        let _ = self
+            .flash
+            .read_page(self.active_start + self.progress.get(), buf);
+    }
+
+    fn read_dfu_page(&self) {
+        let buf = self.dfu_buffer.take().unwrap();
+        let _ = self
+            .flash
+            .read_page(self.dfu_start + self.progress.get(), buf);
+    }
+
+    /// Durably stage `read_active_page`'s content into SCRATCH before
+    /// either ACTIVE or DFU is overwritten for this page.
+    fn write_scratch_page(&self) {
+        let buf = self.active_buffer.take().unwrap();
+        let _ = self.flash.write_page(self.scratch_start, buf);
+    }
+
+    /// Resume-only: recover the content staged by `write_scratch_page`
+    /// after a reset found this page already at `SUBSTEP_SCRATCH_DONE` or
+    /// `SUBSTEP_ACTIVE_DONE`.
+    fn read_scratch_page(&self) {
+        let buf = self.active_buffer.take().unwrap();
+        let _ = self.flash.read_page(self.scratch_start, buf);
+    }
+
+    /// Write what `read_dfu_page` brought back into ACTIVE's slot.
+    fn write_active_page(&self) {
+        let buf = self.dfu_buffer.take().unwrap();
+        let _ = self
+            .flash
+            .write_page(self.active_start + self.progress.get(), buf);
+    }
+
+    /// Write what's in `active_buffer` (ACTIVE's original content, either
+    /// still held from `read_active_page` or recovered by
+    /// `read_scratch_page`) into DFU's slot.
+    fn write_dfu_page(&self) {
+        let buf = self.active_buffer.take().unwrap();
+        let _ = self
+            .flash
+            .write_page(self.dfu_start + self.progress.get(), buf);
+    }
+
+    fn finish(&self, status: usize) {
+        self.operation.set(Operation::None);
+        self.appid.map(|id| {
+            self.apps
+                .enter(*id, |app, _| {
+                    app.callback.map(|cb| {
+                        cb.schedule(status, 0, 0);
+                    });
+                })
+                .unwrap();
+        });
+    }
+}
+
+impl<'a, F: Flash + 'static> Client<F> for Dfu<'a, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        if error != flash::Error::CommandComplete {
+            self.finish(STATUS_FAILED);
+            return;
+        }
+
+        match self.operation.get() {
+            Operation::Swap => match self.swap_step.get() {
+                SwapStep::ReadState => {
+                    let magic = buffer.as_mut()[0];
+                    let progress = read_progress(buffer.as_mut());
+                    let substep = buffer.as_mut()[SUBSTEP_OFFSET];
+                    self.state_buffer.replace(buffer);
+
+                    if (magic == SWAP_MAGIC || magic == REVERT_MAGIC) && progress < self.num_pages {
+                        // Resume an in-progress swap, forward or revert,
+                        // from the last committed page and sub-step.
+                        self.swap_magic.set(magic);
+                        self.progress.set(progress);
+                        self.page_substep.set(substep);
+                        match substep {
+                            SUBSTEP_ACTIVE_DONE => {
+                                self.swap_step.set(SwapStep::ReadScratch);
+                                self.read_scratch_page();
+                            }
+                            SUBSTEP_SCRATCH_DONE => {
+                                // DFU is still untouched, but ACTIVE may
+                                // already be (partly) overwritten, so take
+                                // ACTIVE's original content back from
+                                // SCRATCH, then re-read DFU before ACTIVE
+                                // is (re)written.
+                                self.swap_step.set(SwapStep::ReadActive);
+                                self.read_scratch_page();
+                            }
+                            _ => {
+                                // SUBSTEP_NONE: nothing changed yet.
+                                self.swap_step.set(SwapStep::ReadActive);
+                                self.read_active_page();
+                            }
+                        }
+                    } else if magic == SWAP_MAGIC {
+                        // progress == num_pages: the forward swap ran to
+                        // completion but was never confirmed via
+                        // `mark_booted` before this reset/crash. Revert it
+                        // by swapping back, under `REVERT_MAGIC` so the
+                        // revert's own completion isn't mistaken for
+                        // another unconfirmed forward swap.
+                        self.swap_magic.set(REVERT_MAGIC);
+                        self.progress.set(0);
+                        self.page_substep.set(SUBSTEP_NONE);
+                        self.swap_step.set(SwapStep::WriteState);
+                        self.write_state(REVERT_MAGIC, 0, SUBSTEP_NONE);
+                    } else {
+                        // `BOOT_MAGIC`, a completed revert
+                        // (`REVERT_MAGIC` at `progress == num_pages`), or
+                        // an erased/uninitialized STATE: nothing to do.
+                        self.finish(STATUS_OK);
+                    }
+                }
+                SwapStep::ReadActive => {
+                    self.active_buffer.replace(buffer);
+                    self.swap_step.set(SwapStep::ReadDfu);
+                    self.read_dfu_page();
+                }
+                SwapStep::ReadDfu => {
+                    self.dfu_buffer.replace(buffer);
+                    if self.page_substep.get() == SUBSTEP_SCRATCH_DONE {
+                        // Resuming: SCRATCH already durably holds
+                        // ACTIVE's original content, so go straight to
+                        // overwriting ACTIVE with the DFU content just
+                        // re-read.
+                        self.swap_step.set(SwapStep::WriteActive);
+                        self.write_active_page();
+                    } else {
+                        self.swap_step.set(SwapStep::WriteScratch);
+                        self.write_scratch_page();
+                    }
+                }
+                SwapStep::ReadScratch => {
+                    self.active_buffer.replace(buffer);
+                    self.swap_step.set(SwapStep::WriteDfu);
+                    self.write_dfu_page();
+                }
+                _ => unreachable!(),
+            },
+            Operation::GetState => {
+                let magic = buffer.as_mut()[0];
+                let progress = read_progress(buffer.as_mut());
+                self.state_buffer.replace(buffer);
+
+                self.operation.set(Operation::None);
+                self.appid.map(|id| {
+                    self.apps
+                        .enter(*id, |app, _| {
+                            app.callback.map(|cb| {
+                                cb.schedule(magic as usize, progress, 0);
+                            });
+                        })
+                        .unwrap();
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        if error != flash::Error::CommandComplete {
+            self.finish(STATUS_FAILED);
+            return;
+        }
+
+        match self.operation.get() {
+            Operation::Swap => match self.swap_step.get() {
+                SwapStep::WriteState => {
+                    self.state_buffer.replace(buffer);
+
+                    if self.progress.get() < self.num_pages {
+                        self.swap_step.set(SwapStep::ReadActive);
+                        self.read_active_page();
+                    } else {
+                        self.finish(STATUS_OK);
+                    }
+                }
+                SwapStep::WriteScratch => {
+                    // The buffer handed back is the one `write_scratch_page`
+                    // took from `active_buffer`; ACTIVE's original content
+                    // is now durable in SCRATCH, so it's safe to overwrite
+                    // either side of this page. Persist that before doing
+                    // so.
+                    self.active_buffer.replace(buffer);
+                    self.page_substep.set(SUBSTEP_SCRATCH_DONE);
+                    self.swap_step.set(SwapStep::CommitScratchDone);
+                    self.write_state(
+                        self.swap_magic.get(),
+                        self.progress.get(),
+                        SUBSTEP_SCRATCH_DONE,
+                    );
+                }
+                SwapStep::CommitScratchDone => {
+                    self.state_buffer.replace(buffer);
+                    self.swap_step.set(SwapStep::WriteActive);
+                    self.write_active_page();
+                }
+                SwapStep::WriteActive => {
+                    // The buffer handed back is the one `write_active_page`
+                    // took from `dfu_buffer`.
+                    self.dfu_buffer.replace(buffer);
+                    self.page_substep.set(SUBSTEP_ACTIVE_DONE);
+                    self.swap_step.set(SwapStep::CommitActiveDone);
+                    self.write_state(
+                        self.swap_magic.get(),
+                        self.progress.get(),
+                        SUBSTEP_ACTIVE_DONE,
+                    );
+                }
+                SwapStep::CommitActiveDone => {
+                    self.state_buffer.replace(buffer);
+                    self.swap_step.set(SwapStep::WriteDfu);
+                    self.write_dfu_page();
+                }
+                SwapStep::WriteDfu => {
+                    // The buffer handed back is the one `write_dfu_page`
+                    // took from `active_buffer`.
+                    self.active_buffer.replace(buffer);
+                    self.page_substep.set(SUBSTEP_NONE);
+                    self.swap_step.set(SwapStep::WriteState);
+                    self.progress.set(self.progress.get() + 1);
+                    self.write_state(self.swap_magic.get(), self.progress.get(), SUBSTEP_NONE);
+                }
+                _ => unreachable!(),
+            },
+            Operation::WriteFirmware => {
+                self.dfu_buffer.replace(buffer);
+                self.finish(STATUS_OK);
+            }
+            Operation::MarkBooted => {
+                self.state_buffer.replace(buffer);
+                self.finish(STATUS_OK);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn erase_complete(&self, _error: flash::Error) {
+        // This capsule always writes over existing pages directly and
+        // never calls `erase_page`; provided only to satisfy `Client`.
+    }
+}
+
+impl<'a, F: Flash + 'static> Driver for Dfu<'a, F> {
+    /// Specify memory regions to be used.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer each `write_firmware` copies one page of
+    ///   firmware from, starting at its first byte.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.firmware = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Subscribe to events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Callback fired when the current command completes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback.insert(callback);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Drive the update process.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: `write_firmware`. Writes the first `data2` bytes (at most
+    ///   one page) of the allowed firmware buffer into the DFU partition's
+    ///   page `data1`.
+    /// - `1`: `mark_updated`. Requests a swap between ACTIVE and DFU.
+    /// - `2`: `get_state`. Reports STATE's magic and progress index to
+    ///   the callback.
+    /// - `3`: `mark_booted`. Confirms the currently running image, so the
+    ///   next boot does not swap back.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            // write_firmware
+            0 => {
+                if self.operation.get() != Operation::None {
+                    return ReturnCode::EBUSY;
+                }
+                if data1 >= self.num_pages {
+                    return ReturnCode::EINVAL;
+                }
+
+                self.apps
+                    .enter(appid, |app, _| {
+                        if let Some(firmware) = app.firmware.take() {
+                            let buf = self.dfu_buffer.take().unwrap();
+                            let len = core::cmp::min(data2, buf.as_mut().len());
+                            buf.as_mut()[0..len].copy_from_slice(&firmware.as_ref()[0..len]);
+
+                            self.appid.set(appid);
+                            self.operation.set(Operation::WriteFirmware);
+                            let _ = self.flash.write_page(self.dfu_start + data1, buf);
+
+                            app.firmware.replace(firmware);
+                            ReturnCode::SUCCESS
+                        } else {
+                            ReturnCode::EBUSY
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // mark_updated
+            1 => {
+                if self.operation.get() != Operation::None {
+                    return ReturnCode::EBUSY;
+                }
+
+                self.appid.set(appid);
+                self.operation.set(Operation::Swap);
+                self.swap_magic.set(SWAP_MAGIC);
+                self.swap_step.set(SwapStep::WriteState);
+                self.progress.set(0);
+                self.page_substep.set(SUBSTEP_NONE);
+                self.write_state(SWAP_MAGIC, 0, SUBSTEP_NONE);
+
+                ReturnCode::SUCCESS
+            }
+
+            // get_state
+            2 => {
+                if self.operation.get() != Operation::None {
+                    return ReturnCode::EBUSY;
+                }
+
+                self.appid.set(appid);
+                self.operation.set(Operation::GetState);
+                self.read_state();
+
+                ReturnCode::SUCCESS
+            }
+
+            // mark_booted
+            3 => {
+                if self.operation.get() != Operation::None {
+                    return ReturnCode::EBUSY;
+                }
+
+                self.appid.set(appid);
+                self.operation.set(Operation::MarkBooted);
+                self.write_state(BOOT_MAGIC, 0, SUBSTEP_NONE);
+
+                ReturnCode::SUCCESS
+            }
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+pub struct App {
+    callback: OptionalCell<Callback>,
+    firmware: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: OptionalCell::empty(),
+            firmware: None,
+        }
+    }
+}
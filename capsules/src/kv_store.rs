@@ -5,12 +5,13 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::KVStore as usize;
 
 use core::cell::Cell;
-use core::hash::SipHasher;
+use core::hash::{Hasher, SipHasher};
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::flash::{self, Client, Flash};
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 use tickv;
+use tickv::error_codes::ErrorCode;
 use tickv::success_codes::SuccessCode;
 use tickv::AsyncTicKV;
 use tickv::TicKV;
@@ -23,37 +24,264 @@ pub enum State {
     EraseComplete(usize),
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 enum Operation {
     None,
     Init,
     GetKey,
+    AppendKey,
+    InvalidateKey,
+    GarbageCollect,
+}
+
+/// Status codes passed back to userspace in the completion callback's
+/// first argument, distinguishing the ways a KV operation can finish
+/// beyond the simple success/failure `ReturnCode` the command itself
+/// returns (which only reports whether the operation was *accepted*).
+const STATUS_OK: usize = 0;
+const STATUS_KEY_NOT_FOUND: usize = 1;
+const STATUS_REGION_FULL: usize = 2;
+const STATUS_VALUE_TOO_LARGE: usize = 3;
+const STATUS_FAILED: usize = 4;
+
+/// A `ReadNotReady`/`WriteNotReady`/`EraseNotReady` result just means the
+/// flash operation backing this step of the continuation hasn't
+/// completed yet; it is not a terminal result and must not be reported
+/// to the app.
+fn is_pending(ret: &Result<SuccessCode, ErrorCode>) -> bool {
+    matches!(
+        ret,
+        Err(ErrorCode::ReadNotReady(_))
+            | Err(ErrorCode::WriteNotReady(_))
+            | Err(ErrorCode::EraseNotReady(_))
+    )
+}
+
+/// Map a completed (non-pending) TicKV operation to the status code
+/// delivered to the app callback.
+fn status_code(ret: &Result<SuccessCode, ErrorCode>) -> usize {
+    match ret {
+        Ok(_) => STATUS_OK,
+        Err(ErrorCode::KeyNotFound) => STATUS_KEY_NOT_FOUND,
+        Err(ErrorCode::RegionFull) => STATUS_REGION_FULL,
+        Err(ErrorCode::BufferTooSmall) => STATUS_VALUE_TOO_LARGE,
+        Err(_) => STATUS_FAILED,
+    }
+}
+
+/// The state of a flash region, as tracked by a `KVStoreCache` so that
+/// append and garbage-collection scans can skip regions that cannot
+/// possibly hold or match an entry.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PageState {
+    /// The region has been erased and holds no entries.
+    Erased,
+    /// The region holds at least one entry but still has free space.
+    PartiallyFull,
+    /// The region has no more room for new entries.
+    Full,
+}
+
+/// A cache of previously-learned key locations and region states.
+/// Modeled on sequential-storage's split key-cache / page-state-cache
+/// design, where it lets a `FlashController` skip a flash round-trip it
+/// can already answer without one: `TicKVFlashCtrl::read_region` uses
+/// `get_page_state` this way to hand back a synthetic erased region
+/// instead of issuing a real read, since "erased" is the one region
+/// state whose contents are a known constant.
+///
+/// `get_key_region` and `put_page_state(_, PageState::Full)` are not
+/// consulted by `TicKVFlashCtrl` today: unlike an erased region, neither
+/// a cached key's region nor a region's fullness is a value
+/// `read_region` could safely hand back in place of the region TicKV
+/// actually asked for, because `FlashController` never tells us whether
+/// the region we served satisfied TicKV's scan, so there is no way to
+/// recover if a stale cache entry pointed the wrong way. They remain
+/// part of the trait for a `FlashController` with enough extra context
+/// to use them soundly.
+///
+/// Pass the no-op `NoCacheImpl` as `KVStoreDriver`'s cache type to opt
+/// out, so boards that cannot spare the RAM for a cache pay nothing for
+/// this feature.
+pub trait KVStoreCache {
+    /// Look up which region a key hash was last found or written in.
+    fn get_key_region(&self, key_hash: u64) -> Option<usize>;
+    /// Remember that `key_hash` was last found or written in `region`.
+    fn put_key_region(&self, key_hash: u64, region: usize);
+    /// Forget a key, e.g. because it was just invalidated.
+    fn invalidate_key(&self, key_hash: u64);
+    /// Look up the cached state of a region, if known.
+    fn get_page_state(&self, region: usize) -> Option<PageState>;
+    /// Record the state of a region.
+    fn put_page_state(&self, region: usize, state: PageState);
+}
+
+/// The zero-cost no-op cache: every lookup misses, so callers always
+/// fall back to asking TicKV to scan flash directly.
+pub struct NoCacheImpl;
+
+impl KVStoreCache for NoCacheImpl {
+    fn get_key_region(&self, _key_hash: u64) -> Option<usize> {
+        None
+    }
+    fn put_key_region(&self, _key_hash: u64, _region: usize) {}
+    fn invalidate_key(&self, _key_hash: u64) {}
+    fn get_page_state(&self, _region: usize) -> Option<PageState> {
+        None
+    }
+    fn put_page_state(&self, _region: usize, _state: PageState) {}
+}
+
+/// A small fixed-capacity cache backed by linear-scan arrays, suitable
+/// for boards willing to spend a bit of RAM to skip flash round-trips.
+/// `KEYS` bounds the number of key-hash -> region mappings remembered at
+/// once; `REGIONS` must be at least the number of KV regions in use.
+pub struct LocationCache<const KEYS: usize, const REGIONS: usize> {
+    keys: [Cell<Option<(u64, usize)>>; KEYS],
+    pages: [Cell<Option<PageState>>; REGIONS],
+}
+
+impl<const KEYS: usize, const REGIONS: usize> LocationCache<{ KEYS }, { REGIONS }> {
+    pub fn new() -> Self {
+        Self {
+            keys: [(); KEYS].map(|_| Cell::new(None)),
+            pages: [(); REGIONS].map(|_| Cell::new(None)),
+        }
+    }
+}
+
+impl<const KEYS: usize, const REGIONS: usize> KVStoreCache
+    for LocationCache<{ KEYS }, { REGIONS }>
+{
+    fn get_key_region(&self, key_hash: u64) -> Option<usize> {
+        self.keys.iter().find_map(|slot| match slot.get() {
+            Some((hash, region)) if hash == key_hash => Some(region),
+            _ => None,
+        })
+    }
+
+    fn put_key_region(&self, key_hash: u64, region: usize) {
+        // Prefer reusing a slot that already names this key, then fall
+        // back to the first empty slot, then finally evict slot 0.
+        let slot = self
+            .keys
+            .iter()
+            .find(|slot| matches!(slot.get(), Some((hash, _)) if hash == key_hash))
+            .or_else(|| self.keys.iter().find(|slot| slot.get().is_none()))
+            .unwrap_or(&self.keys[0]);
+
+        slot.set(Some((key_hash, region)));
+    }
+
+    fn invalidate_key(&self, key_hash: u64) {
+        if let Some(slot) = self
+            .keys
+            .iter()
+            .find(|slot| matches!(slot.get(), Some((hash, _)) if hash == key_hash))
+        {
+            slot.set(None);
+        }
+    }
+
+    fn get_page_state(&self, region: usize) -> Option<PageState> {
+        self.pages.get(region).and_then(|slot| slot.get())
+    }
+
+    fn put_page_state(&self, region: usize, state: PageState) {
+        if let Some(slot) = self.pages.get(region) {
+            slot.set(Some(state));
+        }
+    }
+}
+
+/// Hash a userspace-provided key, namespaced by the app that owns it, the
+/// same way for every cache lookup. This is independent of whatever hash
+/// TicKV uses internally; it only needs to be consistent for our own
+/// cache's purposes, and to agree with `namespaced_hasher` about which
+/// app a given key belongs to.
+fn hash_key(appid: AppId, key: &[u8]) -> u64 {
+    let mut hasher = namespaced_hasher(appid);
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// A `SipHasher` pre-seeded with `appid`'s id. Handing this (instead of a
+/// bare `SipHasher::new()`) to TicKV's `append_key`/`get_key`/
+/// `invalidate_key` means the hash TicKV stores is effectively of
+/// `(appid, key)` rather than just `key`, so two apps using the same key
+/// string get isolated entries instead of colliding.
+fn namespaced_hasher(appid: AppId) -> SipHasher {
+    let mut hasher = SipHasher::new();
+    hasher.write_usize(appid.id());
+    hasher
+}
+
+/// Where a `KVStoreDriver`'s flash region lives: which `Flash` instance
+/// backs it, the byte address its region starts at, and the size of one
+/// flash block. Implementing this instead of assuming a fixed absolute
+/// base address (and an assumed 1024-byte page) lets a board place the
+/// KV region on any flash instance -- including one separate from the
+/// flash its code executes from, e.g. an external QSPI chip -- by
+/// supplying a different `FlashConfig`.
+pub trait FlashConfig {
+    type F: Flash + 'static;
+
+    /// The `Flash` instance backing this region.
+    fn flash(&self) -> &'static Self::F;
+    /// Byte address, within `flash()`'s address space, where this
+    /// region starts.
+    fn base_address(&self) -> usize;
+    /// Size in bytes of one flash block/page.
+    fn block_size(&self) -> usize;
+
+    /// Page index of the start of this region, derived from
+    /// `base_address()` and `block_size()`.
+    fn region_offset(&self) -> usize {
+        self.base_address() / self.block_size()
+    }
 }
 
-pub struct TicKVFlashCtrl<'a, F: Flash + 'static, const S: usize> {
-    pub flash: &'a F,
-    pub data_buffer: TakeCell<'static, F::Page>,
+pub struct TicKVFlashCtrl<'a, FC: FlashConfig, C: KVStoreCache + 'static, const S: usize> {
+    pub config: &'a FC,
+    pub data_buffer: TakeCell<'static, <FC::F as Flash>::Page>,
     pub state: Cell<State>,
-    pub region_offset: usize,
+    pub cache: C,
+    /// The hash of the key currently being appended, stashed here by
+    /// `KVStoreDriver::start_operation` so that `write` (which is the only place
+    /// that learns the destination region) can populate the key cache.
+    pending_key_hash: Cell<Option<u64>>,
 }
 
-impl<'a, F: Flash, const S: usize> TicKVFlashCtrl<'a, F, { S }> {
+impl<'a, FC: FlashConfig, C: KVStoreCache, const S: usize> TicKVFlashCtrl<'a, FC, C, { S }> {
     pub fn new(
-        flash: &'a F,
-        data_buffer: &'static mut F::Page,
-        region_offset: usize,
-    ) -> TicKVFlashCtrl<'a, F, { S }> {
+        config: &'a FC,
+        data_buffer: &'static mut <FC::F as Flash>::Page,
+        cache: C,
+    ) -> TicKVFlashCtrl<'a, FC, C, { S }> {
+        // The cache keys its region numbers, and `read_region`/`write`
+        // derive their flash page numbers, off `config.block_size()`.
+        // TicKV's own region numbers (the `region_number` it passes into
+        // `FlashController`) are in units of `S`, so the two only agree
+        // -- and the cache only ever names the region TicKV actually
+        // means -- if a `FlashConfig`'s block size matches `S` exactly.
+        debug_assert_eq!(
+            config.block_size(),
+            S,
+            "FlashConfig::block_size() must equal the KVStoreDriver's S region size"
+        );
+
         Self {
-            flash,
+            config,
             data_buffer: TakeCell::new(data_buffer),
             state: Cell::new(State::None),
-            region_offset,
+            cache,
+            pending_key_hash: Cell::new(None),
         }
     }
 }
 
-impl<'a, F: Flash, const S: usize> tickv::flash_controller::FlashController<{ S }>
-    for TicKVFlashCtrl<'a, F, { S }>
+impl<'a, FC: FlashConfig, C: KVStoreCache, const S: usize>
+    tickv::flash_controller::FlashController<{ S }> for TicKVFlashCtrl<'a, FC, C, { S }>
 {
     fn read_region(
         &self,
@@ -75,10 +303,20 @@ impl<'a, F: Flash, const S: usize> tickv::flash_controller::FlashController<{ S
                     return Ok(());
                 }
 
+                if self.cache.get_page_state(region_number) == Some(PageState::Erased) {
+                    // Known empty: skip the flash round-trip and hand
+                    // back a blank (erased) region directly.
+                    for b in buf.iter_mut() {
+                        *b = 0xff;
+                    }
+                    return Ok(());
+                }
+
                 if self
-                    .flash
+                    .config
+                    .flash()
                     .read_page(
-                        self.region_offset + region_number,
+                        self.config.region_offset() + region_number,
                         self.data_buffer.take().unwrap(),
                     )
                     .is_err()
@@ -87,10 +325,18 @@ impl<'a, F: Flash, const S: usize> tickv::flash_controller::FlashController<{ S
                 }
             }
             _ => {
+                if self.cache.get_page_state(region_number) == Some(PageState::Erased) {
+                    for b in buf.iter_mut() {
+                        *b = 0xff;
+                    }
+                    return Ok(());
+                }
+
                 if self
-                    .flash
+                    .config
+                    .flash()
                     .read_page(
-                        self.region_offset + region_number,
+                        self.config.region_offset() + region_number,
                         self.data_buffer.take().unwrap(),
                     )
                     .is_err()
@@ -110,44 +356,60 @@ impl<'a, F: Flash, const S: usize> tickv::flash_controller::FlashController<{ S
             data_buf.as_mut()[i] = *d;
         }
 
-        if self
-            .flash
-            .write_page((0x20040000 + address) / 1024, data_buf)
-            .is_err()
-        {
+        let block_size = self.config.block_size();
+        let page = (self.config.base_address() + address) / block_size;
+
+        if self.config.flash().write_page(page, data_buf).is_err() {
             return Err(tickv::error_codes::ErrorCode::WriteFail);
         }
 
+        let region_number = address / block_size;
+        self.cache
+            .put_page_state(region_number, PageState::PartiallyFull);
+        if let Some(key_hash) = self.pending_key_hash.take() {
+            self.cache.put_key_region(key_hash, region_number);
+        }
+
         Err(tickv::error_codes::ErrorCode::WriteNotReady(address))
     }
 
     fn erase_region(&self, region_number: usize) -> Result<(), tickv::error_codes::ErrorCode> {
-        self.flash.erase_page(self.region_offset + region_number);
+        self.config
+            .flash()
+            .erase_page(self.config.region_offset() + region_number);
+        self.cache.put_page_state(region_number, PageState::Erased);
 
         Err(tickv::error_codes::ErrorCode::EraseNotReady(region_number))
     }
 }
 
-pub struct KVStoreDriver<'a, F: Flash + 'static, const S: usize> {
-    pub inner: AsyncTicKV<'a, TicKVFlashCtrl<'a, F, S>, SipHasher, S>,
+pub struct KVStoreDriver<'a, FC: FlashConfig, C: KVStoreCache + 'static, const S: usize> {
+    pub inner: AsyncTicKV<'a, TicKVFlashCtrl<'a, FC, C, S>, SipHasher, S>,
     apps: Grant<App>,
     appid: OptionalCell<AppId>,
     operation: Cell<Operation>,
+    /// Head of the pending-request queue, chained through each queued
+    /// app's `App::pending_run_app`, so a second app issuing a command
+    /// while another is in flight gets queued instead of colliding with
+    /// the single in-flight `appid`/`operation` above.
+    queue_head: OptionalCell<AppId>,
+    queue_tail: OptionalCell<AppId>,
 }
 
-impl<'a, F: Flash, const S: usize> KVStoreDriver<'a, F, { S }> {
+impl<'a, FC: FlashConfig, C: KVStoreCache, const S: usize> KVStoreDriver<'a, FC, C, { S }> {
     pub fn new(
-        flash: &'a F,
+        config: &'a FC,
         grant: Grant<App>,
         read_buf: &'static mut [u8; S],
         region_size: usize,
-        data_buffer: &'static mut F::Page,
-        region_offset: usize,
-    ) -> KVStoreDriver<'a, F, { S }> {
-        let tickv = AsyncTicKV::<TicKVFlashCtrl<F, S>, SipHasher, S>::new(
-            TicKVFlashCtrl::new(flash, data_buffer, region_offset),
+        data_buffer: &'static mut <FC::F as Flash>::Page,
+        cache: C,
+    ) -> KVStoreDriver<'a, FC, C, { S }> {
+        let region_offset = config.region_offset();
+        let tickv = AsyncTicKV::<TicKVFlashCtrl<FC, C, S>, SipHasher, S>::new(
+            TicKVFlashCtrl::new(config, data_buffer, cache),
             read_buf,
-            region_offset
+            region_offset,
         );
 
         Self {
@@ -155,6 +417,8 @@ impl<'a, F: Flash, const S: usize> KVStoreDriver<'a, F, { S }> {
             apps: grant,
             appid: OptionalCell::empty(),
             operation: Cell::new(Operation::None),
+            queue_head: OptionalCell::empty(),
+            queue_tail: OptionalCell::empty(),
         }
     }
 
@@ -200,15 +464,253 @@ impl<'a, F: Flash, const S: usize> KVStoreDriver<'a, F, { S }> {
                 self.operation.set(Operation::None);
                 State::None
             }
+            Err(ErrorCode::KeyNotFound)
+            | Err(ErrorCode::RegionFull)
+            | Err(ErrorCode::BufferTooSmall) => {
+                // A terminal, non-pending error: the operation is done,
+                // just unsuccessfully. Leave it to the caller to report
+                // the specific status to the app.
+                self.operation.set(Operation::None);
+                State::None
+            }
             Err(e) => panic!("Error: {:?}", e),
         };
 
         self.inner.tickv.controller.state.set(state);
     }
+
+    /// Finish up a non-`GetKey` operation that only needs to report its
+    /// success/failure back to the app, with no value to return.
+    fn complete_operation(&self, ret: Result<SuccessCode, tickv::error_codes::ErrorCode>) {
+        self.update_state(ret);
+
+        if !is_pending(&ret) {
+            self.appid.map(|id| {
+                self.apps
+                    .enter(*id, |app, _| {
+                        app.callback.map(|cb| {
+                            cb.schedule(status_code(&ret), 0, 0);
+                        });
+                    })
+                    .unwrap();
+            });
+
+            self.operation.set(Operation::None);
+            self.dispatch_next();
+        }
+    }
+
+    /// Queue `op` for `appid` because the hardware is already busy
+    /// running another app's operation. Chains through
+    /// `App::pending_run_app` -- an intrusive linked list through the
+    /// grant region -- rather than keeping separate fixed-capacity queue
+    /// storage. `dispatch_next` starts it once the current operation, and
+    /// every operation queued ahead of it, completes.
+    fn enqueue(&self, appid: AppId, op: Operation, key_len: usize, value_len: usize) -> ReturnCode {
+        let already_queued = match self.apps.enter(appid, |app, _| {
+            app.key_len.set(key_len);
+            app.pending_value_len.set(value_len);
+            app.pending_operation.set(op);
+            app.in_queue.get()
+        }) {
+            Ok(already_queued) => already_queued,
+            Err(err) => return err.into(),
+        };
+
+        if already_queued {
+            // Already linked into the queue from an earlier command; the
+            // fields just updated above are what `dispatch_next` will pick
+            // up when it gets here. Re-linking it would either duplicate
+            // the entry or, if `appid` is also `queue_tail`, chain it onto
+            // itself and loop forever.
+            return ReturnCode::SUCCESS;
+        }
+
+        self.apps
+            .enter(appid, |app, _| app.in_queue.set(true))
+            .unwrap();
+
+        match self.queue_tail.take() {
+            Some(tail) => {
+                self.apps
+                    .enter(tail, |tail_app, _| {
+                        tail_app.pending_run_app = Some(appid);
+                    })
+                    .unwrap();
+            }
+            None => self.queue_head.set(appid),
+        }
+        self.queue_tail.set(appid);
+
+        ReturnCode::SUCCESS
+    }
+
+    /// Start the next queued app's operation, if any, now that the
+    /// hardware has gone idle.
+    fn dispatch_next(&self) {
+        let appid = match self.queue_head.take() {
+            Some(appid) => appid,
+            None => return,
+        };
+
+        let next = self
+            .apps
+            .enter(appid, |app, _| {
+                app.in_queue.set(false);
+                app.pending_run_app.take()
+            })
+            .unwrap_or(None);
+
+        match next {
+            Some(next_id) => self.queue_head.set(next_id),
+            None => self.queue_tail.clear(),
+        }
+
+        let (op, key_len, value_len) = self
+            .apps
+            .enter(appid, |app, _| {
+                let op = app.pending_operation.get();
+                app.pending_operation.set(Operation::None);
+                (
+                    op,
+                    app.key_len.take().unwrap_or(0),
+                    app.pending_value_len.get(),
+                )
+            })
+            .unwrap();
+
+        self.start_operation(op, key_len, value_len, appid);
+    }
+
+    /// Run `op` for `appid` against the hardware, exactly as `command`
+    /// does for a non-busy request. Shared so a queued operation
+    /// `dispatch_next` pulls off is started identically to a fresh one.
+    fn start_operation(
+        &self,
+        op: Operation,
+        key_len: usize,
+        value_len: usize,
+        appid: AppId,
+    ) -> ReturnCode {
+        match op {
+            Operation::AppendKey => self
+                .apps
+                .enter(appid, |app, _| {
+                    if let Some(key) = app.key.take() {
+                        if let Some(value) = app.value.take() {
+                            self.appid.set(appid);
+                            self.operation.set(Operation::AppendKey);
+                            app.key_len.set(key_len);
+
+                            self.inner
+                                .tickv
+                                .controller
+                                .pending_key_hash
+                                .set(Some(hash_key(appid, &key.as_ref()[0..key_len])));
+
+                            let ret = self.inner.tickv.append_key(
+                                &mut namespaced_hasher(appid),
+                                &key.as_ref()[0..key_len],
+                                &value.as_ref()[0..value_len],
+                            );
+
+                            self.update_state(ret);
+
+                            app.value.replace(value);
+                            app.key.replace(key);
+
+                            ReturnCode::SUCCESS
+                        } else {
+                            app.key.replace(key);
+                            ReturnCode::EBUSY
+                        }
+                    } else {
+                        ReturnCode::EBUSY
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Operation::GetKey => self
+                .apps
+                .enter(appid, |app, _| {
+                    if let Some(key) = app.key.take() {
+                        if let Some(mut value) = app.value.take() {
+                            self.appid.set(appid);
+                            self.operation.set(Operation::GetKey);
+                            app.key_len.set(key_len);
+
+                            let ret = self.inner.tickv.get_key(
+                                &mut namespaced_hasher(appid),
+                                &key.as_ref()[0..key_len],
+                                value.as_mut(),
+                            );
+
+                            self.update_state(ret);
+
+                            app.value.replace(value);
+                            app.key.replace(key);
+
+                            ReturnCode::SUCCESS
+                        } else {
+                            app.key.replace(key);
+                            ReturnCode::EBUSY
+                        }
+                    } else {
+                        ReturnCode::EBUSY
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Operation::InvalidateKey => self
+                .apps
+                .enter(appid, |app, _| {
+                    if let Some(key) = app.key.take() {
+                        self.appid.set(appid);
+                        self.operation.set(Operation::InvalidateKey);
+                        app.key_len.set(key_len);
+
+                        self.inner
+                            .tickv
+                            .controller
+                            .cache
+                            .invalidate_key(hash_key(appid, &key.as_ref()[0..key_len]));
+
+                        let ret = self.inner.tickv.invalidate_key(
+                            &mut namespaced_hasher(appid),
+                            &key.as_ref()[0..key_len],
+                        );
+
+                        self.update_state(ret);
+
+                        app.key.replace(key);
+
+                        ReturnCode::SUCCESS
+                    } else {
+                        ReturnCode::EBUSY
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            Operation::GarbageCollect => {
+                self.appid.set(appid);
+                self.operation.set(Operation::GarbageCollect);
+
+                let ret = self.inner.tickv.garbage_collect();
+
+                self.update_state(ret);
+
+                ReturnCode::SUCCESS
+            }
+
+            Operation::None | Operation::Init => unreachable!(),
+        }
+    }
 }
 
-impl<'a, F: Flash, const S: usize> Client<F> for KVStoreDriver<'a, F, { S }> {
-    fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: flash::Error) {
+impl<'a, FC: FlashConfig, C: KVStoreCache, const S: usize> Client<FC::F>
+    for KVStoreDriver<'a, FC, C, { S }>
+{
+    fn read_complete(&self, pagebuffer: &'static mut <FC::F as Flash>::Page, _error: flash::Error) {
         self.inner.tickv.controller.data_buffer.replace(pagebuffer);
 
         match self.operation.get() {
@@ -222,6 +724,7 @@ impl<'a, F: Flash, const S: usize> Client<F> for KVStoreDriver<'a, F, { S }> {
 
                 if ret.is_ok() {
                     self.operation.set(Operation::None);
+                    self.dispatch_next();
                 }
             }
             Operation::GetKey => {
@@ -235,28 +738,30 @@ impl<'a, F: Flash, const S: usize> Client<F> for KVStoreDriver<'a, F, { S }> {
                                 if let Some(mut value) = app.value.take() {
                                     let key_len = app.key_len.take().unwrap();
 
-                                    let ret = self
-                                        .inner
-                                        .continue_operation((
-                                            &mut SipHasher::new(),
-                                            &mut SipHasher::new(),
-                                        ))
-                                        .0;
+                                    let (ret, value_len) = self.inner.continue_operation((
+                                        &mut SipHasher::new(),
+                                        &mut SipHasher::new(),
+                                    ));
 
                                     self.update_state(ret);
 
-                                    if ret.is_ok() {
+                                    if !is_pending(&ret) {
                                         self.appid.map(|id| {
                                             self.apps
                                                 .enter(*id, |app, _| {
                                                     app.callback.map(|cb| {
-                                                        cb.schedule(0, 0, 0);
+                                                        cb.schedule(
+                                                            status_code(&ret),
+                                                            value_len.unwrap_or(0),
+                                                            0,
+                                                        );
                                                     });
                                                 })
                                                 .unwrap();
                                         });
 
                                         self.operation.set(Operation::None);
+                                        self.dispatch_next();
                                     }
                                     app.key_len.set(key_len);
                                     app.value.replace(value);
@@ -267,20 +772,49 @@ impl<'a, F: Flash, const S: usize> Client<F> for KVStoreDriver<'a, F, { S }> {
                         .unwrap();
                 });
             }
+            Operation::AppendKey | Operation::InvalidateKey | Operation::GarbageCollect => {
+                let ret = self
+                    .inner
+                    .continue_operation((&mut SipHasher::new(), &mut SipHasher::new()))
+                    .0;
+
+                self.complete_operation(ret);
+            }
             _ => unreachable!(),
         }
     }
 
-    fn write_complete(&self, pagebuffer: &'static mut F::Page, _error: flash::Error) {
+    fn write_complete(
+        &self,
+        pagebuffer: &'static mut <FC::F as Flash>::Page,
+        _error: flash::Error,
+    ) {
         self.inner.tickv.controller.data_buffer.replace(pagebuffer);
         self.inner.tickv.controller.state.set(State::None);
 
         match self.operation.get() {
-            Operation::Init => {}
+            Operation::Init => {
+                let ret = self
+                    .inner
+                    .continue_operation((&mut SipHasher::new(), &mut SipHasher::new()))
+                    .0;
+
+                self.update_state(ret);
+
+                if ret.is_ok() {
+                    self.dispatch_next();
+                }
+            }
+            Operation::AppendKey | Operation::InvalidateKey | Operation::GarbageCollect => {
+                let ret = self
+                    .inner
+                    .continue_operation((&mut SipHasher::new(), &mut SipHasher::new()))
+                    .0;
+
+                self.complete_operation(ret);
+            }
             _ => unreachable!(),
         }
-
-        self.operation.set(Operation::None);
     }
 
     fn erase_complete(&self, _error: flash::Error) {
@@ -292,13 +826,27 @@ impl<'a, F: Flash, const S: usize> Client<F> for KVStoreDriver<'a, F, { S }> {
                     .0;
 
                 self.update_state(ret);
+
+                if ret.is_ok() {
+                    self.dispatch_next();
+                }
+            }
+            Operation::AppendKey | Operation::InvalidateKey | Operation::GarbageCollect => {
+                let ret = self
+                    .inner
+                    .continue_operation((&mut SipHasher::new(), &mut SipHasher::new()))
+                    .0;
+
+                self.complete_operation(ret);
             }
             _ => unreachable!(),
         }
     }
 }
 
-impl<'a, F: Flash, const S: usize> Driver for KVStoreDriver<'a, F, { S }> {
+impl<'a, FC: FlashConfig, C: KVStoreCache, const S: usize> Driver
+    for KVStoreDriver<'a, FC, C, { S }>
+{
     /// Specify memory regions to be used.
     ///
     /// ### `allow_num`
@@ -367,60 +915,44 @@ impl<'a, F: Flash, const S: usize> Driver for KVStoreDriver<'a, F, { S }> {
         &self,
         command_num: usize,
         key_len: usize,
-        _data2: usize,
+        value_len: usize,
         appid: AppId,
     ) -> ReturnCode {
-        match command_num {
-            // Append key
-            0 => ReturnCode::SUCCESS,
-
-            // Get key
-            1 => self
-                .apps
-                .enter(appid, |app, _| {
-                    if let Some(key) = app.key.take() {
-                        if let Some(mut value) = app.value.take() {
-                            self.appid.set(appid);
-                            self.operation.set(Operation::GetKey);
-                            app.key_len.set(key_len);
-
-                            let ret = self.inner.tickv.get_key(
-                                &mut SipHasher::new(),
-                                &key.as_ref()[0..key_len],
-                                value.as_mut(),
-                            );
-
-                            self.update_state(ret);
-
-                            app.value.replace(value);
-                            app.key.replace(key);
-
-                            ReturnCode::SUCCESS
-                        } else {
-                            app.key.replace(key);
-                            ReturnCode::EBUSY
-                        }
-                    } else {
-                        ReturnCode::EBUSY
-                    }
-                })
-                .unwrap_or_else(|err| err.into()),
-
-            // Invalidate ke
-            2 => ReturnCode::SUCCESS,
-
-            // Trigger garbage collection
-            3 => ReturnCode::SUCCESS,
+        let op = match command_num {
+            0 => Operation::AppendKey,
+            1 => Operation::GetKey,
+            2 => Operation::InvalidateKey,
+            3 => Operation::GarbageCollect,
+            _ => return ReturnCode::ENOSUPPORT,
+        };
 
-            // default
-            _ => ReturnCode::ENOSUPPORT,
+        // The hardware only ever drives one operation at a time; if one
+        // is already in flight for another app, queue this one instead
+        // of clobbering `self.appid`/`self.operation`.
+        if self.operation.get() != Operation::None {
+            return self.enqueue(appid, op, key_len, value_len);
         }
+
+        self.start_operation(op, key_len, value_len, appid)
     }
 }
 
 pub struct App {
     callback: OptionalCell<Callback>,
-    _pending_run_app: Option<AppId>,
+    /// Next app in the pending-request queue this app was chained into by
+    /// `KVStoreDriver::enqueue`, if any.
+    pending_run_app: Option<AppId>,
+    /// Whether this app is currently linked into the pending-request
+    /// queue, so a second `enqueue` call for the same app (e.g. it issues
+    /// another command before its first one is dispatched) updates its
+    /// queued operation in place instead of re-linking it.
+    in_queue: Cell<bool>,
+    /// The operation queued for this app while it waits its turn, and the
+    /// value length that went with it (`key_len` below doubles as the
+    /// queued key length, since it is also needed once the operation
+    /// actually runs).
+    pending_operation: Cell<Operation>,
+    pending_value_len: Cell<usize>,
     key: Option<AppSlice<Shared, u8>>,
     key_len: OptionalCell<usize>,
     value: Option<AppSlice<Shared, u8>>,
@@ -430,10 +962,93 @@ impl Default for App {
     fn default() -> App {
         App {
             callback: OptionalCell::empty(),
-            _pending_run_app: None,
+            pending_run_app: None,
+            in_queue: Cell::new(false),
+            pending_operation: Cell::new(Operation::None),
+            pending_value_len: Cell::new(0),
             key: None,
             key_len: OptionalCell::empty(),
             value: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{KVStoreCache, LocationCache, PageState};
+
+    #[test]
+    fn key_region_round_trip_and_miss() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        assert_eq!(cache.get_key_region(1), None);
+
+        cache.put_key_region(1, 0);
+        assert_eq!(cache.get_key_region(1), Some(0));
+        assert_eq!(cache.get_key_region(2), None);
+    }
+
+    #[test]
+    fn put_key_region_updates_existing_slot_in_place() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        cache.put_key_region(1, 0);
+        cache.put_key_region(1, 3);
+
+        assert_eq!(cache.get_key_region(1), Some(3));
+    }
+
+    #[test]
+    fn put_key_region_evicts_slot_zero_once_full() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        cache.put_key_region(1, 0);
+        cache.put_key_region(2, 1);
+        // Both slots are now taken, so this evicts slot 0's entry for
+        // key 1.
+        cache.put_key_region(3, 2);
+
+        assert_eq!(cache.get_key_region(1), None);
+        assert_eq!(cache.get_key_region(2), Some(1));
+        assert_eq!(cache.get_key_region(3), Some(2));
+    }
+
+    #[test]
+    fn invalidate_key_forgets_only_that_key() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        cache.put_key_region(1, 0);
+        cache.put_key_region(2, 1);
+
+        cache.invalidate_key(1);
+
+        assert_eq!(cache.get_key_region(1), None);
+        assert_eq!(cache.get_key_region(2), Some(1));
+
+        // Invalidating a key that was never cached is a no-op, not a
+        // panic.
+        cache.invalidate_key(99);
+    }
+
+    #[test]
+    fn page_state_round_trip() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        assert_eq!(cache.get_page_state(0), None);
+
+        cache.put_page_state(0, PageState::Erased);
+        cache.put_page_state(1, PageState::Full);
+
+        assert_eq!(cache.get_page_state(0), Some(PageState::Erased));
+        assert_eq!(cache.get_page_state(1), Some(PageState::Full));
+        assert_eq!(cache.get_page_state(2), None);
+    }
+
+    #[test]
+    fn page_state_out_of_range_is_ignored_not_a_panic() {
+        let cache: LocationCache<2, 4> = LocationCache::new();
+
+        cache.put_page_state(100, PageState::Full);
+        assert_eq!(cache.get_page_state(100), None);
+    }
+}